@@ -1,20 +1,29 @@
+use dashmap::DashMap;
+use rustc_hash::FxHasher;
 use sharded_slab::{pool::Ref, Clear, Pool};
 use thread_local::ThreadLocal;
 
 use super::stack::SpanStack;
 use crate::{
     filter::{FilterId, FilterMap, FilterState},
+    layer::Context,
     registry::{
         extensions::{Extensions, ExtensionsInner, ExtensionsMut},
         LookupSpan, SpanData,
     },
     sync::RwLock,
+    Layer,
 };
 use std::{
     cell::{self, Cell, RefCell},
-    sync::atomic::{fence, AtomicUsize, Ordering},
+    collections::VecDeque,
+    hash::BuildHasherDefault,
+    sync::{
+        atomic::{fence, AtomicBool, AtomicI64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
 };
-use std::hash::BuildHasherDefault;
 use tracing_core::{
     dispatcher::{self, Dispatch},
     span::{self, Current, Id},
@@ -64,7 +73,9 @@ use tracing_core::{
 /// being stored, the same ID may occur for multiple spans times in that
 /// data. If spans must be uniquely identified in historical data, the user
 /// code storing this data must assign its own unique identifiers to those
-/// spans. A counter is generally sufficient for this.
+/// spans. A counter is generally sufficient for this, or see
+/// [`Registry::with_generational_ids`], which opts into packing such a
+/// counter into the `Id` itself.
 ///
 /// Similarly, span IDs generated by the registry are not unique outside of
 /// a given process. Distributed tracing systems may require identifiers
@@ -89,13 +100,251 @@ use tracing_core::{
 /// [stored span data]: crate::registry::SpanData::extensions_mut
 #[cfg(feature = "registry")]
 #[cfg_attr(docsrs, doc(cfg(all(feature = "registry", feature = "std"))))]
-#[derive(Debug)]
 pub struct Registry {
     spans: Pool<DataInner>,
     current_spans: ThreadLocal<RefCell<SpanStack>>,
     next_filter_id: u8,
+    anomaly_hook: AnomalyHook,
+    generational_ids: bool,
+    max_live_spans: Option<usize>,
+    capacity_policy: CapacityPolicy,
+    // Ids of open spans, oldest first (it may also still hold ids of spans
+    // that have since closed). Only maintained when `capacity_policy` is
+    // `CapacityPolicy::EvictOldest`, so that it can find an eviction
+    // candidate without scanning the whole pool.
+    open_order: Mutex<VecDeque<Id>>,
+    audit_leaks_on_drop: bool,
+    guard_order_policy: Option<GuardOrderPolicy>,
+    // Per-instance span bookkeeping, backing `Registry::diagnostics`. These
+    // used to be process-wide `static`s, which meant one registry's counts
+    // were contaminated by spans belonging to any other `Registry` in the
+    // same process; they are now scoped to `self` like everything else here.
+    live_spans: AtomicI64,
+    open_spans: AtomicI64,
+    in_spans: AtomicI64,
+    span_tracker: DashMap<Id, SpanInfo, BuildHasherDefault<FxHasher>>,
+}
+
+impl std::fmt::Debug for Registry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Registry")
+            .field("spans", &self.spans)
+            .field("current_spans", &self.current_spans)
+            .field("next_filter_id", &self.next_filter_id)
+            .field("anomaly_hook", &"...")
+            .field("generational_ids", &self.generational_ids)
+            .field("max_live_spans", &self.max_live_spans)
+            .field("capacity_policy", &self.capacity_policy)
+            .field("audit_leaks_on_drop", &self.audit_leaks_on_drop)
+            .field("guard_order_policy", &self.guard_order_policy)
+            .field("live_spans", &self.live_spans)
+            .field("open_spans", &self.open_spans)
+            .field("in_spans", &self.in_spans)
+            .finish()
+    }
+}
+
+/// A boxed callback invoked when the [`Registry`] observes a span refcount
+/// anomaly. See [`Registry::on_refcount_anomaly`].
+#[derive(Clone)]
+struct AnomalyHook(Arc<dyn Fn(Id, AnomalyKind, &'static Metadata<'static>) + Send + Sync>);
+
+impl AnomalyHook {
+    fn call(&self, id: Id, kind: AnomalyKind, metadata: &'static Metadata<'static>) {
+        (self.0)(id, kind, metadata)
+    }
+}
+
+/// The kind of span refcount anomaly reported to a hook registered via
+/// [`Registry::on_refcount_anomaly`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AnomalyKind {
+    /// A span was cloned after its reference count had already reached
+    /// zero.
+    OverClone,
+    /// A reference to a span was dropped or cloned, but no span with that
+    /// id exists in the registry (it may have already been closed and its
+    /// slot reused).
+    CloseMissing,
+    /// A span's reference count overflowed `usize::MAX`.
+    RefcountOverflow,
+    /// A still-open span was forcibly evicted to make room for a new span,
+    /// because the registry reached the capacity configured via
+    /// [`Registry::with_max_live_spans`] and its [`CapacityPolicy`] is
+    /// [`CapacityPolicy::EvictOldest`].
+    Evicted,
+}
+
+/// A ready-made response to a refcount anomaly, for use with
+/// [`Registry::on_refcount_anomaly`].
+///
+/// For example, `registry.on_refcount_anomaly(move |id, kind, meta| {
+/// AnomalyAction::Log.respond(id, kind, meta) })` converts what used to be
+/// a hard `panic!`/`assert!` into a `tracing` warning.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AnomalyAction {
+    /// Abort by panicking, matching the registry's historical behavior.
+    ///
+    /// If the current thread is already panicking, this falls back to
+    /// logging instead, since panicking while unwinding would abort the
+    /// process rather than simply failing the current test or task.
+    Abort,
+    /// Log the anomaly via `tracing` at `WARN` level, and continue.
+    Log,
+    /// Silently ignore the anomaly.
+    Ignore,
+}
+
+impl AnomalyAction {
+    /// Responds to a refcount anomaly of `kind` on the span identified by
+    /// `id`, according to this action.
+    pub fn respond(self, id: Id, kind: AnomalyKind, metadata: &'static Metadata<'static>) {
+        match self {
+            AnomalyAction::Abort if std::thread::panicking() => {
+                tracing::error!(
+                    target: "tracing::registry",
+                    span.id = ?id,
+                    span.name = metadata.name(),
+                    anomaly = ?kind,
+                    "span refcount anomaly while panicking; not aborting to avoid a double panic"
+                );
+            }
+            AnomalyAction::Abort => panic!(
+                "span refcount anomaly ({:?}) for {:?} ({})",
+                kind,
+                id,
+                metadata.name(),
+            ),
+            AnomalyAction::Log => tracing::warn!(
+                target: "tracing::registry",
+                span.id = ?id,
+                span.name = metadata.name(),
+                anomaly = ?kind,
+                "span refcount anomaly"
+            ),
+            AnomalyAction::Ignore => {}
+        }
+    }
+}
+
+/// The policy a capacity-bounded [`Registry`] (see
+/// [`Registry::with_max_live_spans`]) applies when `new_span` is called
+/// while the registry is already at its configured capacity.
+#[derive(Clone)]
+pub enum CapacityPolicy {
+    /// Return a disabled sentinel span instead of allocating a new slot.
+    /// The returned `Id` is never checked out of the pool, so any `Layer`
+    /// looking it up via [`LookupSpan`] will simply find nothing, and the
+    /// span behaves as though it were disabled by a filter.
+    Disable,
+    /// Evict the oldest still-open span to make room for the new one, then
+    /// fire the anomaly hook (see [`Registry::on_refcount_anomaly`]) with
+    /// [`AnomalyKind::Evicted`]. The hook is called only once the span's
+    /// slot has already been reclaimed, never before the eviction, and
+    /// not at all for a candidate that closed on its own first. With the
+    /// default hook, which panics, an eviction therefore panics after the
+    /// slot is reclaimed. Note that this forcibly reclaims the
+    /// evicted span's slot even if other code still holds a `Span` handle
+    /// or `Entered` guard referencing it.
+    ///
+    /// Because the evicted slot is immediately eligible for reuse, a stale
+    /// handle's `Id` could otherwise silently resolve to the *new*,
+    /// unrelated span that occupies the slot next, rather than finding no
+    /// span as the registry's documented `Id` guarantees promise. To avoid
+    /// this, `EvictOldest` requires [`Registry::with_generational_ids`] to
+    /// be enabled; a registry with this policy but without generational IDs
+    /// will panic the first time it needs to evict.
+    EvictOldest,
+    /// Call a user-supplied function to decide whether to make room.
+    /// The function is responsible for applying its own backpressure (for
+    /// example, blocking the calling thread) and should return `true` once
+    /// it is safe to allocate a new span, or `false` to fall back to
+    /// returning a disabled sentinel span, as in [`CapacityPolicy::Disable`].
+    Backpressure(Arc<dyn Fn() -> bool + Send + Sync>),
+}
+
+impl std::fmt::Debug for CapacityPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CapacityPolicy::Disable => f.write_str("CapacityPolicy::Disable"),
+            CapacityPolicy::EvictOldest => f.write_str("CapacityPolicy::EvictOldest"),
+            CapacityPolicy::Backpressure(_) => f.write_str("CapacityPolicy::Backpressure(..)"),
+        }
+    }
+}
+
+/// How a [`Registry`] responds to detecting that an `Entered` guard was
+/// dropped while a more recently entered guard on the same thread is still
+/// alive, when [`Registry::with_guard_order_checks`] is enabled.
+///
+/// Dropping synchronous `Entered` guards out of LIFO order corrupts the
+/// current-span stack, and is a frequent source of mangled span trees,
+/// especially when guards are held across an `.await` point.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GuardOrderPolicy {
+    /// Emit a `tracing::warn!` diagnostic identifying the out-of-order
+    /// guard, and continue.
+    Warn,
+    /// Panic, so that the mistake fails loudly. Intended for use in tests
+    /// and CI, where a corrupted span tree should fail the run rather than
+    /// be silently tolerated.
+    Panic,
+}
+
+impl GuardOrderPolicy {
+    fn respond(self, id: &Id) {
+        match self {
+            GuardOrderPolicy::Warn => tracing::warn!(
+                target: "tracing::registry",
+                span.id = ?id,
+                "span guard dropped out of order: a guard entered more recently on this thread is still alive"
+            ),
+            // If the current thread is already panicking (e.g. unwinding
+            // through a guard held across an error path, or alongside an
+            // unrelated test assertion failure), guards are routinely
+            // dropped out of order as stack frames unwind. Panicking again
+            // here would abort the process instead of just failing the
+            // original panic, so fall back to logging, matching
+            // `AnomalyAction::Abort`'s behavior.
+            GuardOrderPolicy::Panic if std::thread::panicking() => {
+                tracing::error!(
+                    target: "tracing::registry",
+                    span.id = ?id,
+                    "span guard dropped out of order while panicking; not aborting to avoid a double panic"
+                );
+            }
+            GuardOrderPolicy::Panic => panic!(
+                "span guard for {:?} dropped out of order: a guard entered more recently on this thread is still alive",
+                id
+            ),
+        }
+    }
+}
+
+/// Placeholder metadata used when reporting an anomaly for a span whose
+/// `DataInner` entry could not be found (e.g. [`AnomalyKind::CloseMissing`]),
+/// so real metadata is unavailable.
+struct UnknownAnomalySpanCallsite;
+impl tracing_core::callsite::Callsite for UnknownAnomalySpanCallsite {
+    fn set_interest(&self, _: Interest) {
+        unreachable!("the unknown-anomaly-span callsite is never registered")
+    }
+
+    fn metadata(&self) -> &Metadata<'_> {
+        unreachable!("the unknown-anomaly-span callsite's metadata is never accessed")
+    }
 }
 
+static UNKNOWN_ANOMALY_SPAN_CALLSITE: UnknownAnomalySpanCallsite = UnknownAnomalySpanCallsite;
+static UNKNOWN_ANOMALY_SPAN_METADATA: Metadata<'static> = tracing_core::metadata! {
+    name: "<unknown span>",
+    target: "",
+    level: tracing_core::Level::TRACE,
+    fields: &[],
+    callsite: &UNKNOWN_ANOMALY_SPAN_CALLSITE,
+    kind: tracing_core::metadata::Kind::SPAN,
+};
+
 /// Span data stored in a [`Registry`].
 ///
 /// The registry stores well-known data defined by tracing: span relationships,
@@ -129,6 +378,21 @@ struct DataInner {
     // The span's `Extensions` typemap. Allocations for the `HashMap` backing
     // this are pooled and reused in place.
     pub(crate) extensions: RwLock<ExtensionsInner>,
+    // Incremented every time this slot is checked out of the pool by
+    // `create_with`, regardless of whether generation tagging is enabled.
+    // Used, when [`Registry::with_generational_ids`] is set, to disambiguate
+    // a stale `Id` for a since-reused slot from the slot's current occupant.
+    generation: AtomicUsize,
+    // Whether the `Id` this span was created with has a generation packed
+    // into it, i.e. whether `Registry::generational_ids` was set when this
+    // slot was last checked out.
+    tagged: bool,
+    // Set by whichever of a concurrent `CloseGuard::drop` (normal close) or
+    // `Registry::force_evict` (eviction under `CapacityPolicy::EvictOldest`)
+    // wins the race to actually clear this slot, via a single
+    // compare-and-swap, so the other one can tell its work has already been
+    // done rather than clearing the slot a second time.
+    claimed: AtomicBool,
 }
 
 // === impl Registry ===
@@ -139,18 +403,63 @@ impl Default for Registry {
             spans: Pool::new(),
             current_spans: ThreadLocal::new(),
             next_filter_id: 0,
+            anomaly_hook: AnomalyHook(Arc::new(|id, kind, metadata| {
+                AnomalyAction::Abort.respond(id, kind, metadata)
+            })),
+            generational_ids: false,
+            max_live_spans: None,
+            capacity_policy: CapacityPolicy::Disable,
+            open_order: Mutex::new(VecDeque::new()),
+            audit_leaks_on_drop: false,
+            guard_order_policy: None,
+            live_spans: AtomicI64::new(0),
+            open_spans: AtomicI64::new(0),
+            in_spans: AtomicI64::new(0),
+            span_tracker: DashMap::default(),
         }
     }
 }
 
+/// The number of low bits of a (possibly generation-tagged) [`Id`] used to
+/// store the span's slab index. The remaining high bits store the slot's
+/// generation counter, when [`Registry::with_generational_ids`] is enabled.
+const ID_GENERATION_SHIFT: u32 = 32;
+const ID_INDEX_MASK: u64 = (1 << ID_GENERATION_SHIFT) - 1;
+
 #[inline]
-fn idx_to_id(idx: usize) -> Id {
-    Id::from_u64(idx as u64 + 1)
+fn pack_id(idx: usize, generation: u32, tagged: bool) -> Id {
+    let idx = idx as u64 + 1;
+    if tagged {
+        Id::from_u64((generation as u64) << ID_GENERATION_SHIFT | idx)
+    } else {
+        Id::from_u64(idx)
+    }
 }
 
 #[inline]
 fn id_to_idx(id: &Id) -> usize {
-    id.into_u64() as usize - 1
+    (id.into_u64() & ID_INDEX_MASK) as usize - 1
+}
+
+#[inline]
+fn id_generation(id: &Id) -> u32 {
+    (id.into_u64() >> ID_GENERATION_SHIFT) as u32
+}
+
+/// A reserved `Id` returned by `new_span` in place of a real span when the
+/// registry is at capacity and its [`CapacityPolicy`] is
+/// [`CapacityPolicy::Disable`]. It is never checked out of the pool, so it
+/// is always treated as though no span exists with that id.
+const DISABLED_SPAN_ID: u64 = u64::MAX;
+
+#[inline]
+fn disabled_span_id() -> Id {
+    Id::from_u64(DISABLED_SPAN_ID)
+}
+
+#[inline]
+fn is_disabled_span(id: &Id) -> bool {
+    id.into_u64() == DISABLED_SPAN_ID
 }
 
 /// A guard that tracks how many [`Registry`]-backed `Layer`s have
@@ -179,29 +488,128 @@ pub(crate) struct CloseGuard<'a> {
     registry: &'a Registry,
     is_closing: bool,
 }
-use std::sync::atomic::AtomicI64;
-use dashmap::DashMap;
-use lazy_static::lazy_static;
-use rustc_hash::FxHasher;
+#[derive(Debug, Copy, Clone)]
+pub struct SpanInfo {
+    pub too_many_refs: usize,
+    pub panicking: usize,
+    // When this span was opened, used by `Registry::leaked_spans` to filter
+    // out spans that simply haven't had a chance to close yet.
+    opened_at: Instant,
+}
 
-// pub static SPAN_TRACKER: Dash
-pub static LIVE_SPANS: AtomicI64 = AtomicI64::new(0);
-pub static OPEN_SPANS: AtomicI64 = AtomicI64::new(0);
-pub static IN_SPANS: AtomicI64 = AtomicI64::new(0);
+impl SpanInfo {
+    fn new() -> Self {
+        Self {
+            too_many_refs: 0,
+            panicking: 0,
+            opened_at: Instant::now(),
+        }
+    }
+}
 
-lazy_static! {
-    pub static ref SPAN_TRACKER: DashMap<Id, SpanInfo, BuildHasherDefault<FxHasher>> = DashMap::default();
+/// A point-in-time snapshot of a [`Registry`]'s span bookkeeping counters.
+///
+/// Returned by [`Registry::diagnostics`]. The counts are read from separate
+/// atomics updated on the span lifecycle's hot path, so they are
+/// approximate under concurrent load, but are precise enough for a
+/// long-running service to periodically check for unbounded growth caused
+/// by leaked `Span` or `Entered` guards.
+#[derive(Debug, Clone, Copy)]
+pub struct RegistryDiagnostics {
+    /// The number of spans that currently have a live slot allocated in the
+    /// registry's pool.
+    pub live_spans: i64,
+    /// The number of spans that have been created but have not yet closed
+    /// (i.e. their reference count has not reached zero).
+    pub open_spans: i64,
+    /// The number of spans currently entered on some thread.
+    pub entered_spans: i64,
 }
 
-#[derive(Debug, Default, Copy, Clone)]
-pub struct SpanInfo {
-    pub too_many_refs: usize,
-    pub panicking: usize,
+/// Metadata describing a span whose reference count has not reached zero.
+///
+/// Returned by [`Registry::leaked_spans`].
+#[derive(Debug, Clone)]
+pub struct LeakedSpan {
+    /// The leaked span's id.
+    pub id: Id,
+    /// The leaked span's name.
+    pub name: &'static str,
+    /// The leaked span's target.
+    pub target: &'static str,
+    /// The number of outstanding references to the span.
+    pub ref_count: usize,
+    /// How long the span has been open.
+    pub age: Duration,
 }
 
 impl Registry {
     fn get(&self, id: &Id) -> Option<Ref<'_, DataInner>> {
-        self.spans.get(id_to_idx(id))
+        if is_disabled_span(id) {
+            return None;
+        }
+        let span = self.spans.get(id_to_idx(id))?;
+        if self.generational_ids && span.generation.load(Ordering::Relaxed) as u32 != id_generation(id) {
+            // This `Id`'s generation no longer matches the slot's current
+            // occupant: the span it named has since closed and the slot was
+            // reused for a new span. Treat it as not found, rather than
+            // silently returning the new occupant.
+            return None;
+        }
+        Some(span)
+    }
+
+    /// Returns a snapshot of this registry's span-tracking counters.
+    ///
+    /// Long-running services can poll this periodically to notice unbounded
+    /// growth in the number of live or open spans, which usually indicates
+    /// that a `Span` or `Entered` guard is being leaked somewhere in the
+    /// instrumented code.
+    pub fn diagnostics(&self) -> RegistryDiagnostics {
+        RegistryDiagnostics {
+            live_spans: self.live_spans.load(Ordering::Acquire),
+            open_spans: self.open_spans.load(Ordering::Acquire),
+            entered_spans: self.in_spans.load(Ordering::Acquire),
+        }
+    }
+
+    /// Returns metadata for every span that has been open for at least
+    /// `min_age` without its reference count reaching zero.
+    ///
+    /// Note that simply being open is not, by itself, evidence of a leak:
+    /// every span that's merely in the middle of ordinary work is "open but
+    /// not yet closed" for its whole lifetime. `min_age` exists so that a
+    /// long-running service can poll this periodically and only surface
+    /// spans that have stuck around far longer than any legitimate span in
+    /// that service ever should (callers should pick a threshold well above
+    /// their longest normal span duration). Pass [`Duration::ZERO`] to
+    /// report every currently open span regardless of age, e.g. right
+    /// before shutting the registry down, when "still open" does mean
+    /// "leaked".
+    ///
+    /// For each such span, this joins the tracked [`Id`] back to the span's
+    /// [`DataInner`] metadata (name, target, and current `ref_count`), so
+    /// that callers can identify the instrumentation site responsible for
+    /// the leak.
+    pub fn leaked_spans(&self, min_age: Duration) -> Vec<LeakedSpan> {
+        self.span_tracker
+            .iter()
+            .filter_map(|entry| {
+                let age = entry.value().opened_at.elapsed();
+                if age < min_age {
+                    return None;
+                }
+                let id = entry.key().clone();
+                let span = self.get(&id)?;
+                Some(LeakedSpan {
+                    id,
+                    name: span.metadata.name(),
+                    target: span.metadata.target(),
+                    ref_count: span.ref_count.load(Ordering::Acquire),
+                    age,
+                })
+            })
+            .collect()
     }
 
     /// Returns a guard which tracks how many `Layer`s have
@@ -220,6 +628,181 @@ impl Registry {
         }
     }
 
+    /// Configures this registry to invoke `hook` whenever it observes a span
+    /// refcount anomaly (over-cloning an already-closed span, closing a
+    /// span that no longer exists, or a refcount overflow), instead of the
+    /// default behavior of panicking.
+    ///
+    /// This lets embedders convert what would otherwise be a
+    /// process-killing `panic!`/`assert!` into structured telemetry, e.g.
+    /// by passing [`AnomalyAction::Log`] or a custom closure that reports
+    /// the anomaly to a metrics system.
+    pub fn on_refcount_anomaly(
+        mut self,
+        hook: impl Fn(Id, AnomalyKind, &'static Metadata<'static>) + Send + Sync + 'static,
+    ) -> Self {
+        self.anomaly_hook = AnomalyHook(Arc::new(hook));
+        self
+    }
+
+    /// Configures whether this registry packs a per-slot generation counter
+    /// into the high bits of the [`Id`]s it assigns to spans.
+    ///
+    /// By default, span IDs map 1:1 onto slab indices, so an `Id` is only
+    /// unambiguous while that span is alive; once closed and its slot
+    /// reused, the same `Id` will resolve to a different span. When this is
+    /// enabled, each `Id`'s low 32 bits are the slab index as before, but
+    /// the high 32 bits carry a counter that is incremented every time the
+    /// slot is checked out. A stale `Id` for a since-reused slot then fails
+    /// to resolve (lookups return `None`) instead of silently returning the
+    /// new occupant, so that within one process an `Id` names a single span
+    /// until that slot's 32-bit counter wraps around. IDs are still not
+    /// unique across processes.
+    pub fn with_generational_ids(mut self, enabled: bool) -> Self {
+        self.generational_ids = enabled;
+        self
+    }
+
+    /// Configures a maximum number of live spans this registry will hold at
+    /// once, giving operators a memory ceiling for services that can't
+    /// tolerate unbounded growth from buggy instrumentation leaking spans.
+    ///
+    /// Once the limit is reached, `new_span` consults this registry's
+    /// [`CapacityPolicy`] (see [`Registry::with_capacity_policy`], which
+    /// defaults to [`CapacityPolicy::Disable`]) to decide how to respond.
+    pub fn with_max_live_spans(mut self, max: usize) -> Self {
+        self.max_live_spans = Some(max);
+        self
+    }
+
+    /// Configures how this registry responds to `new_span` calls made while
+    /// it is already at the capacity set by [`Registry::with_max_live_spans`].
+    /// Has no effect unless a maximum capacity is also configured.
+    ///
+    /// # Panics
+    ///
+    /// If `policy` is [`CapacityPolicy::EvictOldest`], this registry must
+    /// also have [`Registry::with_generational_ids`] enabled; evicting
+    /// without generation-tagged ids will panic the first time this
+    /// registry needs to evict a span to make room.
+    pub fn with_capacity_policy(mut self, policy: CapacityPolicy) -> Self {
+        self.capacity_policy = policy;
+        self
+    }
+
+    /// Applies this registry's [`CapacityPolicy`] to make room for a new
+    /// span. Returns `true` if the caller should proceed to allocate a new
+    /// span, or `false` if it should fall back to a disabled sentinel span.
+    fn make_room(&self) -> bool {
+        match &self.capacity_policy {
+            CapacityPolicy::Disable => false,
+            CapacityPolicy::EvictOldest => {
+                assert!(
+                    self.generational_ids,
+                    "CapacityPolicy::EvictOldest requires Registry::with_generational_ids(true); \
+                    without generation-tagged ids, a stale handle to an evicted span could silently \
+                    resolve to the new span that reuses its slot"
+                );
+                loop {
+                    let victim = match self.open_order.lock().unwrap().pop_front() {
+                        Some(victim) => victim,
+                        None => return true,
+                    };
+                    let metadata = match self.spans.get(id_to_idx(&victim)) {
+                        Some(span) => span.metadata,
+                        // Already closed on its own; not a useful eviction
+                        // candidate, keep looking for one that's still open.
+                        None => continue,
+                    };
+                    // `force_evict` races with a concurrent `try_close`
+                    // reaching zero for this same span and claims the slot
+                    // atomically, so it may report that there was nothing
+                    // left to evict; if so, `victim` finished closing on its
+                    // own between the checks above and the claim, and we
+                    // just keep looking for another candidate.
+                    if self.force_evict(&victim) {
+                        self.anomaly_hook
+                            .call(victim.clone(), AnomalyKind::Evicted, metadata);
+                        return true;
+                    }
+                }
+            }
+            CapacityPolicy::Backpressure(allow) => allow(),
+        }
+    }
+
+    /// Forcibly reclaims the slot for `id`, regardless of its current
+    /// reference count. Used by [`CapacityPolicy::EvictOldest`] to make
+    /// room under memory pressure.
+    ///
+    /// Returns `false`, doing nothing, if `id`'s slot has already been
+    /// vacated or claimed by a concurrent close or eviction. This atomic
+    /// claim is what keeps a `try_close`/`CloseGuard::drop` racing to close
+    /// this same span from also clearing its slot: only whichever of the
+    /// two wins the claim actually clears it, so the slot can never be
+    /// double-cleared out from under a third span that has since reused it.
+    fn force_evict(&self, id: &Id) -> bool {
+        let idx = id_to_idx(id);
+        let span = match self.spans.get(idx) {
+            Some(span) => span,
+            None => return false,
+        };
+        if self.generational_ids && span.generation.load(Ordering::Relaxed) as u32 != id_generation(id)
+        {
+            // The slot has already been vacated and reused by a different
+            // span since `id` was queued as an eviction candidate.
+            return false;
+        }
+        if span
+            .claimed
+            .compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
+            .is_err()
+        {
+            // Lost the race: a concurrent close already claimed this slot.
+            return false;
+        }
+        self.spans.clear(idx);
+        self.span_tracker.remove(id);
+        self.live_spans.fetch_sub(1, Ordering::Release);
+        self.open_spans.fetch_sub(1, Ordering::Release);
+        true
+    }
+
+    /// Configures this registry to audit for leaked spans when it is
+    /// dropped.
+    ///
+    /// When enabled, dropping this `Registry` walks its record of open spans
+    /// and emits a
+    /// `tracing::warn!` diagnostic, via whichever subscriber is the default
+    /// at the time, for every span whose reference count never reached
+    /// zero (see [`Registry::leaked_spans`]), reporting that span's id,
+    /// name, target, and outstanding reference count. This mirrors
+    /// drop-correctness checks, and would catch the common real-world bug
+    /// of a `Span` handle or `Entered` guard leaked into a `'static`
+    /// closure, or held across an `.await` point, that keeps a span (and
+    /// everything it references) alive forever.
+    ///
+    /// This is disabled by default, since the walk on drop has a cost
+    /// proportional to the number of spans still open, and emitting
+    /// diagnostics via `tracing` from within a `Drop` impl can be
+    /// surprising in a process that is already shutting down.
+    pub fn with_leak_audit(mut self, enabled: bool) -> Self {
+        self.audit_leaks_on_drop = enabled;
+        self
+    }
+
+    /// Configures this registry to detect, per thread, when an `Entered`
+    /// guard is dropped while a more recently entered guard on the same
+    /// thread is still alive, and respond according to `policy`.
+    ///
+    /// This check is only ever compiled in for debug builds (it is a no-op
+    /// in builds with `debug_assertions` off), so it is meant to be enabled
+    /// in tests and CI rather than left on in production.
+    pub fn with_guard_order_checks(mut self, policy: GuardOrderPolicy) -> Self {
+        self.guard_order_policy = Some(policy);
+        self
+    }
+
     pub(crate) fn has_per_layer_filters(&self) -> bool {
         self.next_filter_id > 0
     }
@@ -235,6 +818,14 @@ thread_local! {
     /// For additional details, see [`CloseGuard`].
     ///
     static CLOSE_COUNT: Cell<usize> = Cell::new(0);
+
+    /// The ids of `Entered` guards currently live on this thread, in the
+    /// order they were entered. Used by [`Registry::with_guard_order_checks`]
+    /// to detect a guard being dropped while a more recently entered one on
+    /// the same thread is still alive. Only populated when that check is
+    /// enabled, and compiled out entirely in release builds.
+    #[cfg(debug_assertions)]
+    static ENTERED_GUARDS: RefCell<Vec<Id>> = RefCell::new(Vec::new());
 }
 
 impl Subscriber for Registry {
@@ -263,7 +854,14 @@ impl Subscriber for Registry {
             attrs.parent().map(|id| self.clone_span(id))
         };
 
-        let id = self
+        if let Some(max) = self.max_live_spans {
+            if self.live_spans.load(Ordering::Acquire) >= max as i64 && !self.make_room() {
+                return disabled_span_id();
+            }
+        }
+
+        let mut generation = 0u32;
+        let idx = self
             .spans
             // Check out a `DataInner` entry from the pool for the new span. If
             // there are free entries already allocated in the pool, this will
@@ -283,12 +881,36 @@ impl Subscriber for Registry {
                 let refs = data.ref_count.get_mut();
                 debug_assert_eq!(*refs, 0);
                 *refs = 1;
+
+                let gen = data.generation.get_mut();
+                *gen = gen.wrapping_add(1);
+                generation = *gen as u32;
+                data.tagged = self.generational_ids;
+                *data.claimed.get_mut() = false;
             })
             .expect("Unable to allocate another span");
-        let id = idx_to_id(id);
-        SPAN_TRACKER.insert(id.clone(), SpanInfo::default());
-        LIVE_SPANS.fetch_add(1, Ordering::Release);
-        OPEN_SPANS.fetch_add(1, Ordering::Release);
+        let id = pack_id(idx, generation, self.generational_ids);
+        self.span_tracker.insert(id.clone(), SpanInfo::new());
+        self.live_spans.fetch_add(1, Ordering::Release);
+        self.open_spans.fetch_add(1, Ordering::Release);
+        // Only `EvictOldest` ever reads `open_order` (to find an eviction
+        // candidate); under `Disable` or `Backpressure`, pushing to it would
+        // just be an unbounded memory leak of one `Id` per span ever
+        // created.
+        if matches!(self.capacity_policy, CapacityPolicy::EvictOldest) {
+            let mut open_order = self.open_order.lock().unwrap();
+            // Opportunistically drop entries for spans that have already
+            // closed on their own, so `open_order` doesn't otherwise grow by
+            // one entry for every span ever created when capacity is rarely
+            // (or never) actually hit. This only trims from the front, since
+            // `open_order` is oldest-first and a span can't become an
+            // eviction candidate until every span opened before it is gone.
+            while matches!(open_order.front(), Some(front) if !self.span_tracker.contains_key(front))
+            {
+                open_order.pop_front();
+            }
+            open_order.push_back(id.clone());
+        }
         id
     }
 
@@ -321,39 +943,77 @@ impl Subscriber for Registry {
         {
             self.clone_span(id);
         }
-        IN_SPANS.fetch_add(1, Ordering::Release);
+        #[cfg(debug_assertions)]
+        {
+            if self.guard_order_policy.is_some() {
+                ENTERED_GUARDS.with(|stack| stack.borrow_mut().push(id.clone()));
+            }
+        }
+        self.in_spans.fetch_add(1, Ordering::Release);
     }
 
     fn exit(&self, id: &span::Id) {
+        #[cfg(debug_assertions)]
+        {
+            if let Some(policy) = &self.guard_order_policy {
+                ENTERED_GUARDS.with(|stack| {
+                    let mut stack = stack.borrow_mut();
+                    if let Some(pos) = stack.iter().rposition(|entered| entered == id) {
+                        let dropped_in_order = pos + 1 == stack.len();
+                        stack.remove(pos);
+                        if !dropped_in_order {
+                            policy.respond(id);
+                        }
+                    }
+                });
+            }
+        }
         if let Some(spans) = self.current_spans.get() {
             if spans.borrow_mut().pop(id) {
                 dispatcher::get_default(|dispatch| dispatch.try_close(id.clone()));
             }
         }
-        IN_SPANS.fetch_sub(1, Ordering::Release);
+        self.in_spans.fetch_sub(1, Ordering::Release);
     }
 
     fn clone_span(&self, id: &span::Id) -> span::Id {
-        let span = self
-            .get(id)
-            .unwrap_or_else(|| panic!(
-                "tried to clone {:?}, but no span exists with that ID\n\
-                This may be caused by consuming a parent span (`parent: span`) rather than borrowing it (`parent: &span`).",
-                id,
-            ));
+        if is_disabled_span(id) {
+            // The disabled sentinel span isn't backed by any pool entry, so
+            // there is no refcount to bump; just hand back another copy of
+            // the sentinel id.
+            return id.clone();
+        }
+        let span = match self.get(id) {
+            Some(span) => span,
+            None => {
+                // This may be caused by consuming a parent span (`parent:
+                // span`) rather than borrowing it (`parent: &span`), or by
+                // racing with another thread that has already closed this
+                // span.
+                self.anomaly_hook.call(
+                    id.clone(),
+                    AnomalyKind::CloseMissing,
+                    &UNKNOWN_ANOMALY_SPAN_METADATA,
+                );
+                return id.clone();
+            }
+        };
         // Like `std::sync::Arc`, adds to the ref count (on clone) don't require
         // a strong ordering; if we call` clone_span`, the reference count must
         // always at least 1. The only synchronization necessary is between
         // calls to `try_close`: we have to ensure that all threads have
         // dropped their refs to the span before the span is closed.
         let refs = span.ref_count.fetch_add(1, Ordering::Relaxed);
-        assert_ne!(
-            refs, 0,
-            "tried to clone a span ({:?}) that already closed",
-            id
-        );
-        let span_info = *SPAN_TRACKER.get(&id).unwrap();
-        SPAN_TRACKER.insert(id.clone(), span_info);
+        if refs == 0 {
+            self.anomaly_hook
+                .call(id.clone(), AnomalyKind::OverClone, span.metadata);
+        }
+        // The tracker entry is already gone if the span closed before this
+        // (over-)clone, which the anomaly hook above has just reported.
+        let span_info = self.span_tracker.get(&id).map(|info| *info);
+        if let Some(span_info) = span_info {
+            self.span_tracker.insert(id.clone(), span_info);
+        }
         id.clone()
     }
 
@@ -374,23 +1034,38 @@ impl Subscriber for Registry {
     ///
     /// The allocated span slot will be reused when a new span is created.
     fn try_close(&self, id: span::Id) -> bool {
+        if is_disabled_span(&id) {
+            // There is nothing to close; report it as closed so that
+            // `Layer`/`Subscriber` plumbing built atop the registry behaves
+            // as though the (never-allocated) span simply closed instantly.
+            return true;
+        }
         let span = match self.get(&id) {
             Some(span) => span,
-            None if std::thread::panicking() => {
-                SPAN_TRACKER.get_mut(&id).unwrap().panicking += 1;
-                return false
-            },
+            // A "close of a missing span" anomaly, whether or not the
+            // current thread is already panicking. Route it through the
+            // anomaly hook rather than reaching into `span_tracker` directly
+            // (which may likewise have no entry for `id`, e.g. if the span
+            // was never created or was already fully closed and removed
+            // elsewhere) and unwrapping, since a panic during unwind would
+            // abort the process.
             None => {
-                panic!("tried to drop a ref to {:?}, but no such span exists!", id)
+                self.anomaly_hook.call(
+                    id,
+                    AnomalyKind::CloseMissing,
+                    &UNKNOWN_ANOMALY_SPAN_METADATA,
+                );
+                return false;
             },
         };
 
         let refs = span.ref_count.fetch_sub(1, Ordering::Release);
-        if !std::thread::panicking() {
-            assert!(refs < std::usize::MAX, "reference count overflow!");
+        if !std::thread::panicking() && refs == std::usize::MAX {
+            self.anomaly_hook
+                .call(id.clone(), AnomalyKind::RefcountOverflow, span.metadata);
         }
         if refs > 1 {
-            SPAN_TRACKER.get_mut(&id).unwrap().too_many_refs += 1;
+            self.span_tracker.get_mut(&id).unwrap().too_many_refs += 1;
             return false;
         }
 
@@ -398,8 +1073,8 @@ impl Subscriber for Registry {
         // from std::Arc); this ensures that all other `try_close` calls on
         // other threads happen-before we actually remove the span.
         fence(Ordering::Acquire);
-        SPAN_TRACKER.remove(&id);
-        OPEN_SPANS.fetch_sub(1, Ordering::Release);
+        self.span_tracker.remove(&id);
+        self.open_spans.fetch_sub(1, Ordering::Release);
         true
     }
 }
@@ -445,19 +1120,58 @@ impl<'a> Drop for CloseGuard<'a> {
             // If the current close count is 1, this stack frame is the last
             // `on_close` call. If the span is closing, it's okay to remove the
             // span.
-            if c == 1 && self.is_closing {
-                self.registry.spans.clear(id_to_idx(&self.id));
-                LIVE_SPANS.fetch_sub(1, Ordering::Release);
+            if c == 1 && self.is_closing && !is_disabled_span(&self.id) {
+                let idx = id_to_idx(&self.id);
+                // Claim this slot the same way `Registry::force_evict` does,
+                // so a concurrent eviction racing to reclaim this same span
+                // (under `CapacityPolicy::EvictOldest`) can't also clear it:
+                // whichever of the two wins the claim is the one that
+                // actually clears the slot.
+                let claimed = self.registry.spans.get(idx).map_or(false, |span| {
+                    span.claimed
+                        .compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
+                        .is_ok()
+                });
+                if claimed {
+                    self.registry.spans.clear(idx);
+                    self.registry.live_spans.fetch_sub(1, Ordering::Release);
+                }
             }
         });
     }
 }
 
+impl Drop for Registry {
+    fn drop(&mut self) {
+        if !self.audit_leaks_on_drop {
+            return;
+        }
+
+        // A span still open when its registry is dropped is definitely
+        // leaked, regardless of how recently it was opened.
+        for leaked in self.leaked_spans(Duration::ZERO) {
+            tracing::warn!(
+                target: "tracing::registry",
+                span.id = ?leaked.id,
+                span.name = leaked.name,
+                span.target = leaked.target,
+                span.ref_count = leaked.ref_count,
+                span.age = ?leaked.age,
+                "span leaked: still had outstanding references when its registry was dropped"
+            );
+        }
+    }
+}
+
 // === impl Data ===
 
 impl<'a> SpanData<'a> for Data<'a> {
     fn id(&self) -> Id {
-        idx_to_id(self.inner.key())
+        pack_id(
+            self.inner.key(),
+            self.inner.generation.load(Ordering::Relaxed) as u32,
+            self.inner.tagged,
+        )
     }
 
     fn metadata(&self) -> &'static Metadata<'static> {
@@ -525,6 +1239,9 @@ impl Default for DataInner {
             parent: None,
             ref_count: AtomicUsize::new(0),
             extensions: RwLock::new(ExtensionsInner::new()),
+            generation: AtomicUsize::new(0),
+            tagged: false,
+            claimed: AtomicBool::new(false),
         }
     }
 }
@@ -569,6 +1286,132 @@ impl Clear for DataInner {
     }
 }
 
+// === impl SpanLifecycle ===
+
+/// A [`Layer`] that observes the full lifecycle of spans stored in a
+/// [`Registry`]: when a span is opened, when it is closed (its last
+/// reference is dropped), and when its slab slot is actually reclaimed for
+/// reuse.
+///
+/// This distinguishes *closing* (no more [`Span`] handles reference the
+/// span) from *reclaiming* (the registry has cleared the span's storage and
+/// may hand it to a new span). A span's extensions, and any data a
+/// downstream `Layer` stashed in them, remain readable between these two
+/// points, which is why [`on_close`] and [`on_span_reclaimed`] are reported
+/// separately rather than collapsed into one event.
+///
+/// `SpanLifecycle` preserves the invariant that a parent is never reported
+/// closed before its children: each child's [`DataInner`] holds a reference
+/// to its parent, which is only released when the child's slot is
+/// reclaimed, so the parent cannot close until every one of its children
+/// has closed. Downstream subscribers (flush-on-close exporters, span GC
+/// metrics) can therefore rely on deterministic close ordering instead of
+/// re-implementing this tracking themselves. Reclaim order does not follow
+/// the same rule: reclaiming a child's slot releases its parent reference
+/// before the child's extensions are dropped, so a parent closed by its
+/// last child is reported reclaimed before that child is.
+///
+/// [`Span`]: https://docs.rs/tracing/latest/tracing/span/struct.Span.html
+/// [`on_close`]: SpanLifecycle::on_span_closed
+/// [`on_span_reclaimed`]: SpanLifecycle::on_span_reclaimed
+#[derive(Clone, Default)]
+pub struct SpanLifecycle {
+    on_opened: Option<Arc<dyn Fn(&Id, &'static str) + Send + Sync>>,
+    on_closed: Option<Arc<dyn Fn(&Id) + Send + Sync>>,
+    on_reclaimed: Option<Arc<dyn Fn(&Id) + Send + Sync>>,
+}
+
+/// Dropped alongside a span's extensions when its slot is reclaimed,
+/// invoking the registered `on_span_reclaimed` callback at exactly that
+/// point.
+struct ReclaimNotifier {
+    id: Id,
+    hook: Arc<dyn Fn(&Id) + Send + Sync>,
+}
+
+impl Drop for ReclaimNotifier {
+    fn drop(&mut self) {
+        (self.hook)(&self.id);
+    }
+}
+
+impl std::fmt::Debug for SpanLifecycle {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SpanLifecycle")
+            .field("on_opened", &self.on_opened.as_ref().map(|_| "..."))
+            .field("on_closed", &self.on_closed.as_ref().map(|_| "..."))
+            .field("on_reclaimed", &self.on_reclaimed.as_ref().map(|_| "..."))
+            .finish()
+    }
+}
+
+impl SpanLifecycle {
+    /// Returns a new `SpanLifecycle` with no callbacks registered.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets a callback invoked when a new span is opened, with the span's
+    /// id and name.
+    pub fn on_span_opened(mut self, hook: impl Fn(&Id, &'static str) + Send + Sync + 'static) -> Self {
+        self.on_opened = Some(Arc::new(hook));
+        self
+    }
+
+    /// Sets a callback invoked when a span closes, i.e. when its final
+    /// [`Span`] reference is dropped.
+    ///
+    /// [`Span`]: https://docs.rs/tracing/latest/tracing/span/struct.Span.html
+    pub fn on_span_closed(mut self, hook: impl Fn(&Id) + Send + Sync + 'static) -> Self {
+        self.on_closed = Some(Arc::new(hook));
+        self
+    }
+
+    /// Sets a callback invoked when a closed span's slab slot is actually
+    /// reclaimed by the registry, and may be reused by a future span.
+    pub fn on_span_reclaimed(mut self, hook: impl Fn(&Id) + Send + Sync + 'static) -> Self {
+        self.on_reclaimed = Some(Arc::new(hook));
+        self
+    }
+}
+
+impl<S> Layer<S> for SpanLifecycle
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, _attrs: &span::Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let span = match ctx.span(id) {
+            Some(span) => span,
+            // The registry may hand back a disabled sentinel id instead of a
+            // real span (e.g. when a `Registry` configured with
+            // `with_max_live_spans` is at capacity and its `CapacityPolicy`
+            // is `Disable`), in which case there is no span data to report.
+            None => return,
+        };
+
+        if let Some(on_opened) = &self.on_opened {
+            on_opened(id, span.name());
+        }
+
+        if let Some(on_reclaimed) = self.on_reclaimed.clone() {
+            span.extensions_mut().insert(ReclaimNotifier {
+                id: id.clone(),
+                hook: on_reclaimed,
+            });
+        }
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        if ctx.span(&id).is_none() {
+            // The span's data is already gone; nothing to report.
+            return;
+        }
+        if let Some(on_closed) = &self.on_closed {
+            on_closed(&id);
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -944,4 +1787,215 @@ mod tests {
             state.assert_closed_in_order(&["child", "parent", "grandparent"]);
         });
     }
+
+    #[test]
+    fn span_lifecycle_does_not_panic_on_disabled_span() {
+        // A `Registry` at capacity with `CapacityPolicy::Disable` (the
+        // default) hands back the disabled sentinel id from `new_span`, for
+        // which `ctx.span()` always returns `None`. `SpanLifecycle` must not
+        // panic when this happens.
+        let opened = Arc::new(Mutex::new(0usize));
+        let opened2 = opened.clone();
+        let lifecycle = SpanLifecycle::new().on_span_opened(move |_, _| {
+            *opened2.lock().unwrap() += 1;
+        });
+        let subscriber = lifecycle.with_subscriber(Registry::default().with_max_live_spans(1));
+
+        with_default(subscriber, || {
+            let _span1 = tracing::info_span!("span1");
+            // This second span exceeds capacity and is disabled; observing
+            // it must not panic.
+            let _span2 = tracing::info_span!("span2");
+        });
+
+        assert_eq!(*opened.lock().unwrap(), 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "requires Registry::with_generational_ids")]
+    fn evict_oldest_requires_generational_ids() {
+        let subscriber = Registry::default()
+            .with_max_live_spans(1)
+            .with_capacity_policy(CapacityPolicy::EvictOldest);
+
+        with_default(subscriber, || {
+            let _span1 = tracing::info_span!("span1");
+            let _span2 = tracing::info_span!("span2");
+        });
+    }
+
+    #[test]
+    fn evict_oldest_with_generational_ids_disambiguates_reused_slot() {
+        // The default anomaly hook panics, both on the eviction itself and
+        // when the stale `span1` handle is later dropped, so ignore them.
+        let subscriber = Registry::default()
+            .with_max_live_spans(1)
+            .with_generational_ids(true)
+            .with_capacity_policy(CapacityPolicy::EvictOldest)
+            .on_refcount_anomaly(|_, _, _| {});
+
+        with_default(subscriber, || {
+            let span1 = tracing::info_span!("span1");
+            let id1 = span1.id().expect("span1 should have an id");
+            // This exceeds capacity, evicting `span1`'s slot.
+            let span2 = tracing::info_span!("span2");
+            let id2 = span2.id().expect("span2 should have an id");
+
+            // Even though `span2` reuses `span1`'s slab slot, the
+            // generation tag means they are never the same `Id`, so the
+            // stale `span1` handle cannot be confused with `span2`.
+            assert_ne!(id1, id2);
+        });
+    }
+
+    #[test]
+    fn diagnostics_are_scoped_per_registry() {
+        // Two independent `Registry` instances must not contaminate each
+        // other's `diagnostics`/`leaked_spans`, since each tracks only its
+        // own spans rather than sharing process-wide state.
+        let dispatch_a = dispatcher::Dispatch::new(Registry::default());
+        let dispatch_b = dispatcher::Dispatch::new(Registry::default());
+
+        let _span_a = dispatcher::with_default(&dispatch_a, || tracing::info_span!("a"));
+        let _span_b1 = dispatcher::with_default(&dispatch_b, || tracing::info_span!("b1"));
+        let _span_b2 = dispatcher::with_default(&dispatch_b, || tracing::info_span!("b2"));
+
+        let registry_a = dispatch_a.downcast_ref::<Registry>().unwrap();
+        let registry_b = dispatch_b.downcast_ref::<Registry>().unwrap();
+
+        assert_eq!(registry_a.diagnostics().open_spans, 1);
+        assert_eq!(registry_b.diagnostics().open_spans, 2);
+        assert_eq!(registry_a.leaked_spans(Duration::ZERO).len(), 1);
+        assert_eq!(registry_b.leaked_spans(Duration::ZERO).len(), 2);
+    }
+
+    #[test]
+    fn leaked_spans_filters_by_age() {
+        // An ordinary open span isn't "leaked" just because it hasn't
+        // closed yet; `leaked_spans` should only surface spans older than
+        // the caller's chosen threshold.
+        let dispatch = dispatcher::Dispatch::new(Registry::default());
+        let _span = dispatcher::with_default(&dispatch, || tracing::info_span!("still_running"));
+        let registry = dispatch.downcast_ref::<Registry>().unwrap();
+
+        assert!(registry.leaked_spans(Duration::from_secs(60)).is_empty());
+        assert_eq!(registry.leaked_spans(Duration::ZERO).len(), 1);
+    }
+
+    #[test]
+    fn clone_span_of_missing_span_uses_anomaly_hook() {
+        // Cloning a stale `Id` whose span has already closed must go
+        // through the anomaly hook, just like `try_close` does, rather than
+        // unconditionally panicking.
+        let subscriber = Registry::default().on_refcount_anomaly(|_, _, _| {});
+        let dispatch = dispatcher::Dispatch::new(subscriber);
+
+        let id = dispatcher::with_default(&dispatch, || {
+            let span = tracing::info_span!("span");
+            let id = span.id().expect("span should have an id");
+            drop(span);
+            id
+        });
+
+        let cloned = dispatch.clone_span(&id);
+        assert_eq!(cloned, id);
+    }
+
+    #[test]
+    fn generational_ids_detect_stale_handle_after_reuse() {
+        // The registry only clears a closed span's slot when it is wrapped
+        // in a `Layer`, so wrap it to let the second span reuse the slot.
+        let registry = DoesNothing.with_subscriber(Registry::default().with_generational_ids(true));
+        let dispatch = dispatcher::Dispatch::new(registry);
+
+        let stale_id = dispatcher::with_default(&dispatch, || {
+            let span = tracing::info_span!("first");
+            let id = span.id().expect("span should have an id");
+            drop(span);
+            id
+        });
+
+        // Create a new span, which reuses the same (now-cleared) slab slot.
+        dispatcher::with_default(&dispatch, || {
+            let _span2 = tracing::info_span!("second");
+        });
+
+        let registry = dispatch.downcast_ref::<Registry>().unwrap();
+        assert!(LookupSpan::span_data(registry, &stale_id).is_none());
+    }
+
+    #[test]
+    fn leak_audit_warns_on_drop() {
+        // A `Registry` with leak auditing enabled should emit a diagnostic,
+        // via whichever subscriber is the ambient default at drop time, for
+        // every span that's still open when it's dropped.
+        #[derive(Clone, Default)]
+        struct CaptureEvents(Arc<Mutex<usize>>);
+        impl Subscriber for CaptureEvents {
+            fn enabled(&self, _: &Metadata<'_>) -> bool {
+                true
+            }
+            fn new_span(&self, _: &Attributes<'_>) -> Id {
+                Id::from_u64(1)
+            }
+            fn record(&self, _: &Id, _: &tracing_core::span::Record<'_>) {}
+            fn record_follows_from(&self, _: &Id, _: &Id) {}
+            fn event(&self, _: &Event<'_>) {
+                *self.0.lock().unwrap() += 1;
+            }
+            fn enter(&self, _: &Id) {}
+            fn exit(&self, _: &Id) {}
+        }
+
+        struct TestCallsite;
+        impl tracing_core::callsite::Callsite for TestCallsite {
+            fn set_interest(&self, _: Interest) {}
+            fn metadata(&self) -> &Metadata<'_> {
+                &TEST_METADATA
+            }
+        }
+        static TEST_CALLSITE: TestCallsite = TestCallsite;
+        static TEST_METADATA: Metadata<'static> = tracing_core::metadata! {
+            name: "leaked_test_span",
+            target: "tracing_subscriber::registry::sharded::tests",
+            level: tracing_core::Level::TRACE,
+            fields: &[],
+            callsite: &TEST_CALLSITE,
+            kind: tracing_core::metadata::Kind::SPAN,
+        };
+
+        let capture = CaptureEvents::default();
+        let capture_dispatch = dispatcher::Dispatch::new(capture.clone());
+
+        dispatcher::with_default(&capture_dispatch, || {
+            let registry = Registry::default().with_leak_audit(true);
+            let values = TEST_METADATA.fields().value_set(&[]);
+            let attrs = Attributes::new_root(&TEST_METADATA, &values);
+            // Open a span directly via the `Subscriber` trait, without ever
+            // calling `try_close`, so it is still open when `registry`
+            // drops at the end of this block.
+            let _id = registry.new_span(&attrs);
+        });
+
+        assert_eq!(*capture.0.lock().unwrap(), 1);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "dropped out of order")]
+    fn guard_order_panic_policy_panics_on_out_of_order_drop() {
+        let subscriber = Registry::default().with_guard_order_checks(GuardOrderPolicy::Panic);
+
+        with_default(subscriber, || {
+            let span1 = tracing::info_span!("span1");
+            let span2 = tracing::info_span!("span2");
+
+            let enter1 = span1.enter();
+            let _enter2 = span2.enter();
+
+            // `enter1` is dropped while `_enter2`, entered more recently on
+            // this thread, is still alive.
+            drop(enter1);
+        });
+    }
 }